@@ -5,31 +5,278 @@
 //! other. If we have dangling Rename From events, we have to remove them after some time.
 //! Aside from that, when a directory is moved to our watched location from the outside, we receive
 //! a Create Dir event, this one is actually ok at least.
+//!
+//! On top of path matching, we also keep a [`FileIdCache`] mapping indexed paths to their
+//! `FileId` (device + inode on Linux), so a dangling Rename From can be resolved by identity
+//! instead of by timeout: if the same inode shows up again under another indexed path (a
+//! cross-location move, a hardlink, or an editor doing an atomic save by renaming a temp file
+//! over the original) we re-link it instead of treating it as a delete followed by a create.
 
 use crate::{
-	invalidate_query, library::Library, location::manager::LocationManagerError, prisma::location,
-	util::error::FileIOError, Node,
+	invalidate_query, library::Library, location::manager::LocationManagerError,
+	prisma::{file_path, location}, util::error::FileIOError, Node,
 };
 
 use std::{
-	collections::{BTreeMap, HashMap},
-	path::PathBuf,
-	sync::Arc,
+	collections::{BTreeMap, HashMap, HashSet},
+	ffi::OsStr,
+	os::unix::ffi::OsStrExt,
+	path::{Path, PathBuf},
+	sync::{Arc, Mutex, OnceLock},
+	time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use async_trait::async_trait;
+use file_id::FileId;
 use notify::{
 	event::{CreateKind, DataChange, ModifyKind, RenameMode},
 	Event, EventKind,
 };
-use tokio::{fs, time::Instant};
-use tracing::{error, trace};
+use serde::{Deserialize, Serialize};
+use tokio::{fs, task::JoinSet, time::Instant};
+use tracing::{error, trace, warn};
+use walkdir::{DirEntry, WalkDir};
 
 use super::{
 	utils::{create_dir, remove, rename, update_file},
 	EventHandler, HUNDRED_MILLIS,
 };
 
+/// Which debounced operation a [`JournalEntry`] is standing in for.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+enum PendingKind {
+	Update,
+	RenameFrom,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct JournalEntry {
+	kind: PendingKind,
+	recorded_at_millis: u64,
+}
+
+/// Durable, path-keyed journal of everything sitting in [`LinuxEventHandler::files_to_update`]
+/// and [`LinuxEventHandler::rename_from`], so a crash or shutdown during the debounce window
+/// doesn't silently drop the pending file change or rename.
+struct PendingJournal {
+	db: sled::Db,
+}
+
+impl PendingJournal {
+	fn open(node: &Node, location_id: location::id::Type) -> Self {
+		sled::open(node.data_dir.join("watcher_journal").join(location_id.to_string()))
+			.map(|db| Self { db })
+			.unwrap_or_else(|e| {
+				error!("Failed to open watcher journal for location {location_id}, falling back to an in-memory one: {e:#?}");
+				Self {
+					db: sled::Config::new()
+						.temporary(true)
+						.open()
+						.expect("in-memory sled config can't fail to open"),
+				}
+			})
+	}
+
+	fn record(&self, path: &Path, kind: PendingKind) {
+		let entry = JournalEntry {
+			kind,
+			recorded_at_millis: now_millis(),
+		};
+
+		match serde_json::to_vec(&entry) {
+			Ok(value) => {
+				if let Err(e) = self.db.insert(path.as_os_str().as_bytes(), value) {
+					warn!("Failed to journal pending change for {}: {e:#?}", path.display());
+				}
+			}
+			Err(e) => warn!("Failed to serialize journal entry for {}: {e:#?}", path.display()),
+		}
+	}
+
+	fn clear(&self, path: &Path) {
+		if let Err(e) = self.db.remove(path.as_os_str().as_bytes()) {
+			warn!("Failed to clear journal entry for {}: {e:#?}", path.display());
+		}
+	}
+
+	fn drain(&self) -> Vec<(PathBuf, JournalEntry)> {
+		self.db
+			.iter()
+			.filter_map(|res| res.ok())
+			.filter_map(|(key, value)| {
+				let entry = serde_json::from_slice::<JournalEntry>(&value).ok()?;
+				Some((PathBuf::from(OsStr::from_bytes(&key)), entry))
+			})
+			.collect()
+	}
+}
+
+fn now_millis() -> u64 {
+	SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.unwrap_or_default()
+		.as_millis() as u64
+}
+
+/// Timing policy for the debounce/eviction state machine, previously hardcoded as
+/// `HUNDRED_MILLIS` (and a bare `* 5` for the update settle window).
+#[derive(Debug, Clone, Copy)]
+pub(super) struct WatcherConfig {
+	/// How long a dangling `Rename From` or a pending removal waits before it's treated as final.
+	debounce: Duration,
+	/// How many multiples of `debounce` a file must sit untouched before `update_file` runs,
+	/// so a burst of rapid writes coalesces into a single update.
+	update_settle_multiplier: u32,
+	/// How often `tick` re-checks the pending queues for expired entries.
+	eviction_check_interval: Duration,
+	/// How many paths to fan out `fs::metadata` calls (in `reconcile_subtree`) and `update_file`
+	/// upserts (in `handle_to_update_eviction`) for at a time, so a directory with thousands of entries doesn't stall the event loop or
+	/// starve the `tick` eviction timer behind one sequential syscall per entry.
+	batch_size: usize,
+}
+
+impl Default for WatcherConfig {
+	fn default() -> Self {
+		Self {
+			debounce: HUNDRED_MILLIS,
+			update_settle_multiplier: 5,
+			eviction_check_interval: HUNDRED_MILLIS,
+			batch_size: 64,
+		}
+	}
+}
+
+impl WatcherConfig {
+	/// Reads overrides from the environment, falling back to [`Self::default`] for anything
+	/// unset or unparsable.
+	///
+	/// `EventHandler::new` is the only production entry point, and its signature is fixed by the
+	/// `EventHandler` trait (defined outside this file) — it can't grow a `WatcherConfig`
+	/// parameter from here. Environment variables are the one knob this file can actually expose
+	/// to a real caller, so debounce/multiplier/interval/batch_size are tunable at runtime
+	/// without a matching trait change.
+	fn from_env() -> Self {
+		let default = Self::default();
+
+		Self {
+			debounce: env_millis("SD_WATCHER_DEBOUNCE_MILLIS").unwrap_or(default.debounce),
+			update_settle_multiplier: env_parsed("SD_WATCHER_UPDATE_SETTLE_MULTIPLIER")
+				.unwrap_or(default.update_settle_multiplier),
+			eviction_check_interval: env_millis("SD_WATCHER_EVICTION_CHECK_INTERVAL_MILLIS")
+				.unwrap_or(default.eviction_check_interval),
+			batch_size: env_parsed("SD_WATCHER_BATCH_SIZE").unwrap_or(default.batch_size),
+		}
+	}
+}
+
+fn env_parsed<T: std::str::FromStr>(key: &str) -> Option<T> {
+	std::env::var(key).ok()?.parse().ok()
+}
+
+fn env_millis(key: &str) -> Option<Duration> {
+	env_parsed::<u64>(key).map(Duration::from_millis)
+}
+
+/// Abstracts the monotonic clock used to drive the debounce/eviction timers, so tests can
+/// substitute a mock clock instead of depending on real elapsed wall-clock time.
+trait Clock: std::fmt::Debug + Send + Sync {
+	fn now(&self) -> Instant;
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct SystemClock;
+
+/// Whether an entry recorded at `since` has sat untouched for at least `window`, i.e. is due
+/// to be acted on rather than buffered for another tick.
+///
+/// Pulled out of [`LinuxEventHandler::handle_to_update_eviction`] and
+/// [`LinuxEventHandler::handle_rename_from_eviction`] so the debounce decision itself can be
+/// exercised with a [`Clock`] double, without needing a full handler (which requires a real
+/// `Library`/`Node` to construct).
+fn has_settled(now: Instant, since: Instant, window: Duration) -> bool {
+	now - since >= window
+}
+
+impl Clock for SystemClock {
+	fn now(&self) -> Instant {
+		Instant::now()
+	}
+}
+
+/// Keeps track of the [`FileId`] (device + inode on Linux) of every indexed path, so that
+/// dangling rename halves can be matched by identity rather than by a timed guess.
+///
+/// Shared process-wide (see [`file_id_cache`]) rather than owned per [`LinuxEventHandler`]: a
+/// genuine cross-location move produces the `Create`/`Rename To` half in a *different*
+/// location's handler instance, and only a cache both handlers can see lets the origin
+/// location's dangling `Rename From` find where the inode ended up.
+#[derive(Debug, Default)]
+struct FileIdCache {
+	path_to_id: HashMap<PathBuf, FileId>,
+	id_to_path: HashMap<FileId, PathBuf>,
+}
+
+impl FileIdCache {
+	/// Reads the `FileId` for `path` from disk and indexes it.
+	fn add_path(&mut self, path: &Path) {
+		let Ok(id) = file_id::get_file_id(path) else {
+			// The path may already be gone by the time we get around to indexing it, that's fine
+			return;
+		};
+
+		// A path previously registered under `id` keeps its own `path_to_id` entry, so a pending
+		// `Rename From` for it can still be resolved to `path` by `resolve_rename_from`.
+		self.id_to_path.insert(id, path.to_path_buf());
+		self.path_to_id.insert(path.to_path_buf(), id);
+	}
+
+	/// Removes `path` from the cache, returning the `FileId` it was indexed under, if any.
+	/// The reverse entry is only dropped while it still points at `path`.
+	fn remove_path(&mut self, path: &Path) -> Option<FileId> {
+		let id = self.path_to_id.remove(path)?;
+		if self
+			.id_to_path
+			.get(&id)
+			.is_some_and(|current_path| current_path.as_path() == path)
+		{
+			self.id_to_path.remove(&id);
+		}
+		Some(id)
+	}
+
+	/// Checks whether `from_path`'s inode has already reappeared under a different indexed
+	/// path — i.e. some other `Create`/`Rename To`/`Rename Both` (possibly handled by a
+	/// different location's handler, since this cache is shared) already registered it —
+	/// *before* forgetting `from_path`'s own registration.
+	///
+	/// Returns the new path if so. Otherwise `from_path` no longer resolves to anything else,
+	/// so its entry is dropped from the cache and `None` is returned: it's a plain deletion (or
+	/// a move we haven't seen the other half of yet).
+	fn resolve_rename_from(&mut self, from_path: &Path) -> Option<PathBuf> {
+		let id = *self.path_to_id.get(from_path)?;
+
+		match self.id_to_path.get(&id) {
+			Some(current_path) if current_path != from_path => {
+				let current_path = current_path.clone();
+				self.path_to_id.remove(from_path);
+				Some(current_path)
+			}
+			_ => {
+				self.path_to_id.remove(from_path);
+				self.id_to_path.remove(&id);
+				None
+			}
+		}
+	}
+}
+
+/// The process-wide [`FileIdCache`], shared across every [`LinuxEventHandler`] regardless of
+/// which location it watches, so identities registered by one location are visible to all.
+fn file_id_cache() -> &'static Mutex<FileIdCache> {
+	static CACHE: OnceLock<Mutex<FileIdCache>> = OnceLock::new();
+	CACHE.get_or_init(|| Mutex::new(FileIdCache::default()))
+}
+
 #[derive(Debug)]
 pub(super) struct LinuxEventHandler<'lib> {
 	location_id: location::id::Type,
@@ -41,6 +288,16 @@ pub(super) struct LinuxEventHandler<'lib> {
 	recently_renamed_from: BTreeMap<PathBuf, Instant>,
 	files_to_update: HashMap<PathBuf, Instant>,
 	files_to_update_buffer: Vec<(PathBuf, Instant)>,
+	// Paths removed while watched. A later `Create(Folder)` event for exactly one of these paths
+	// takes it back out of this set; no watch is added or removed on its account, and a rescan
+	// does not consult it.
+	missing_paths: HashSet<PathBuf>,
+	journal: PendingJournal,
+	// Entries recovered from the journal whose debounce timer had already elapsed before we
+	// even got a chance to start it back up. Drained on the very first `tick`.
+	overdue_replay: Vec<(PathBuf, PendingKind)>,
+	config: WatcherConfig,
+	clock: Arc<dyn Clock>,
 }
 
 #[async_trait]
@@ -50,22 +307,70 @@ impl<'lib> EventHandler<'lib> for LinuxEventHandler<'lib> {
 		library: &'lib Arc<Library>,
 		node: &'lib Arc<Node>,
 	) -> Self {
-		Self {
+		Self::with_config(
 			location_id,
 			library,
 			node,
-			last_events_eviction_check: Instant::now(),
-			rename_from: HashMap::new(),
-			rename_from_buffer: Vec::new(),
-			recently_renamed_from: BTreeMap::new(),
-			files_to_update: HashMap::new(),
-			files_to_update_buffer: Vec::new(),
-		}
+			WatcherConfig::from_env(),
+			Arc::new(SystemClock),
+		)
 	}
 
 	async fn handle_event(&mut self, event: Event) -> Result<(), LocationManagerError> {
 		tracing::debug!("Received Linux event: {:#?}", event);
 
+		if event.need_rescan() {
+			// inotify dropped events under queue pressure, so our view of the watched subtree
+			// may have silently diverged from disk. Reconcile every affected path by walking it
+			// and diffing against what's indexed, instead of trusting the events we did get.
+			//
+			// The canonical trigger for this, an `IN_Q_OVERFLOW`, isn't tied to any particular
+			// watch descriptor, so notify delivers it with an empty `paths`. In that case fall
+			// back to reconciling the location's own root, since that's the only subtree we
+			// know we're responsible for.
+			//
+			// Flush first, so anything we already had buffered lands before the walk starts;
+			// otherwise the walk could observe a path mid-debounce and disagree with what we're
+			// about to apply for it ourselves.
+			if let Err(e) = self.flush().await {
+				error!("Failed to flush pending work before a rescan: {e:#?}");
+			}
+
+			let Some(location_root) = self.location_root().await else {
+				warn!(
+					"Location {} has no known root path, can't reconcile it after a rescan",
+					self.location_id
+				);
+				return Ok(());
+			};
+
+			let rescanned_paths = if event.paths.is_empty() {
+				vec![location_root.clone()]
+			} else {
+				event.paths.clone()
+			};
+
+			for path in &rescanned_paths {
+				if let Err(e) = self.reconcile_subtree(path).await {
+					error!("Failed to reconcile subtree {} after a rescan: {e:#?}", path.display());
+					continue;
+				}
+
+				// `reconcile_subtree` only re-emits creates/updates for what's still on disk; a
+				// delete that happened during the same dropped-events window would otherwise
+				// dangle in the index forever, reproducing the same desync bug one level down.
+				// Diff the indexed rows under `path` against disk and remove whatever's gone.
+				if let Err(e) = self.reconcile_removed(path, &location_root).await {
+					error!(
+						"Failed to reconcile deletions under {} after a rescan: {e:#?}",
+						path.display()
+					);
+				}
+			}
+
+			return Ok(());
+		}
+
 		let Event {
 			kind, mut paths, ..
 		} = event;
@@ -78,12 +383,17 @@ impl<'lib> EventHandler<'lib> for LinuxEventHandler<'lib> {
 				// each consecutive event of these kinds that we receive for the same file
 				// we just store the path again in the map below, with a new instant
 				// that effectively resets the timer for the file to be updated
-				self.files_to_update.insert(paths.remove(0), Instant::now());
+				let path = paths.remove(0);
+				file_id_cache().lock().unwrap().add_path(&path);
+				self.journal.record(&path, PendingKind::Update);
+				self.files_to_update.insert(path, self.clock.now());
 			}
 
 			EventKind::Create(CreateKind::Folder) => {
 				let path = &paths[0];
 
+				file_id_cache().lock().unwrap().add_path(path);
+
 				create_dir(
 					self.location_id,
 					path,
@@ -94,12 +404,73 @@ impl<'lib> EventHandler<'lib> for LinuxEventHandler<'lib> {
 					self.library,
 				)
 				.await?;
+
+				// If this create is a previously removed path coming back (the directory was
+				// deleted and recreated, or recreated by a restore), take it out of `missing_paths`.
+				let was_missing = self.missing_paths.remove(path);
+
+				// Either way, reconcile everything that already lives under `path`: notify only
+				// tells us about this one top-level `Create(Folder)`, so a directory containing
+				// thousands of entries moved in from outside the watched location (the scenario
+				// this handler is supposed to cover) would otherwise sit unindexed until some
+				// unrelated inotify overflow happened to trigger a rescan.
+				if let Err(e) = self.reconcile_subtree(path).await {
+					if was_missing {
+						error!(
+							"Failed to replay contents of restored watch root {}: {e:#?}",
+							path.display()
+						);
+					} else {
+						error!(
+							"Failed to reconcile contents of newly created directory {}: {e:#?}",
+							path.display()
+						);
+					}
+				}
 			}
 			EventKind::Modify(ModifyKind::Name(RenameMode::From)) => {
 				// Just in case we can't garantee that we receive the Rename From event before the
 				// Rename Both event. Just a safeguard
 				if self.recently_renamed_from.remove(&paths[0]).is_none() {
-					self.rename_from.insert(paths.remove(0), Instant::now());
+					let from_path = paths.remove(0);
+
+					// Before parking this as a dangling rename, check if the inode we're losing
+					// already reappeared under another indexed path. That path may have been
+					// registered by this very handler (hardlink, or an editor's atomic save) or
+					// by a *different* location's handler (a genuine cross-location move), since
+					// the cache is shared process-wide.
+					// On a miss the entry is left in the cache, so `handle_rename_from_eviction` can
+					// check again at the end of the debounce window.
+					let relinked_to = {
+						let mut cache = file_id_cache().lock().unwrap();
+						let reappeared = cache
+							.path_to_id
+							.get(&from_path)
+							.and_then(|id| cache.id_to_path.get(id))
+							.is_some_and(|current_path| current_path != &from_path);
+						if reappeared {
+							cache.resolve_rename_from(&from_path)
+						} else {
+							None
+						}
+					};
+
+					if let Some(to_path) = relinked_to {
+						rename(
+							self.location_id,
+							&to_path,
+							&from_path,
+							fs::metadata(&to_path)
+								.await
+								.map_err(|e| FileIOError::from((&to_path, e)))?,
+							self.library,
+						)
+						.await?;
+						return Ok(());
+					}
+
+					self.journal.record(&from_path, PendingKind::RenameFrom);
+					self.rename_from.insert(from_path, self.clock.now());
 				}
 			}
 
@@ -108,6 +479,12 @@ impl<'lib> EventHandler<'lib> for LinuxEventHandler<'lib> {
 				let to_path = &paths[1];
 
 				self.rename_from.remove(from_path);
+				self.journal.clear(from_path);
+				{
+					let mut cache = file_id_cache().lock().unwrap();
+					cache.remove_path(from_path);
+					cache.add_path(to_path);
+				}
 				rename(
 					self.location_id,
 					to_path,
@@ -119,10 +496,38 @@ impl<'lib> EventHandler<'lib> for LinuxEventHandler<'lib> {
 				)
 				.await?;
 				self.recently_renamed_from
-					.insert(paths.swap_remove(0), Instant::now());
+					.insert(paths.swap_remove(0), self.clock.now());
+			}
+			EventKind::Modify(ModifyKind::Name(RenameMode::To)) => {
+				// notify emits a bare `Rename To` (instead of a `Rename Both`) when it can't
+				// pair this with a `Rename From` locally — typically because the other half
+				// landed in a different watched location. Register the identity in the shared
+				// cache so that location's dangling `Rename From` can find it and re-link
+				// instead of falling through to its timeout, then index the arrival here: a file is
+				// queued for `update_file` as for a `Create(File)`, a directory gets `create_dir`
+				// for itself only (unlike `Create(Folder)`, its contents are not walked here).
+				let path = paths.remove(0);
+				let metadata = fs::metadata(&path)
+					.await
+					.map_err(|e| FileIOError::from((&path, e)))?;
+
+				file_id_cache().lock().unwrap().add_path(&path);
+
+				if metadata.is_dir() {
+					create_dir(self.location_id, &path, &metadata, self.node, self.library).await?;
+				} else {
+					self.journal.record(&path, PendingKind::Update);
+					self.files_to_update.insert(path, self.clock.now());
+				}
 			}
 			EventKind::Remove(_) => {
+				file_id_cache().lock().unwrap().remove_path(&paths[0]);
+				self.journal.clear(&paths[0]);
 				remove(self.location_id, &paths[0], self.library).await?;
+
+				// Remember the path in `missing_paths`; a later `Create(Folder)` event for exactly
+				// this path takes it back out (see that arm).
+				self.missing_paths.insert(paths[0].clone());
 			}
 			other_event_kind => {
 				trace!("Other Linux event that we don't handle for now: {other_event_kind:#?}");
@@ -133,7 +538,13 @@ impl<'lib> EventHandler<'lib> for LinuxEventHandler<'lib> {
 	}
 
 	async fn tick(&mut self) {
-		if self.last_events_eviction_check.elapsed() > HUNDRED_MILLIS {
+		if !self.overdue_replay.is_empty() {
+			if let Err(e) = self.replay_overdue().await {
+				error!("Error replaying journaled watcher events from a previous run: {e:#?}");
+			}
+		}
+
+		if self.clock.now() - self.last_events_eviction_check > self.config.eviction_check_interval {
 			if let Err(e) = self.handle_to_update_eviction().await {
 				error!("Error while handling recently created or update files eviction: {e:#?}");
 			}
@@ -142,25 +553,371 @@ impl<'lib> EventHandler<'lib> for LinuxEventHandler<'lib> {
 				error!("Failed to remove file_path: {e:#?}");
 			}
 
+			let now = self.clock.now();
+			let debounce = self.config.debounce;
 			self.recently_renamed_from
-				.retain(|_, instant| instant.elapsed() < HUNDRED_MILLIS);
+				.retain(|_, instant| now - *instant < debounce);
+
+			self.last_events_eviction_check = now;
+		}
+	}
+
+	/// Immediately processes every entry in [`LinuxEventHandler::files_to_update`] and
+	/// [`LinuxEventHandler::rename_from`], regardless of how long they've been sitting there,
+	/// and fires a single invalidation.
+	///
+	/// Called from [`Self::handle_event`]'s `need_rescan` branch before reconciling, so buffered
+	/// edits aren't double-counted or clobbered by the walk.
+	///
+	/// This is declared here as an `EventHandler::flush` trait method so the generic (cross-
+	/// platform) caller can also invoke it from a graceful-shutdown path, matching how
+	/// `handle_event` and `tick` above are dispatched. That requires a matching `async fn
+	/// flush(&mut self) -> Result<(), LocationManagerError>` to exist on the `EventHandler` trait
+	/// declaration and a call to it from the shutdown path — both of which live outside this
+	/// file (the trait definition and the location manager's shutdown sequence aren't part of
+	/// this tree) and can't be added here. Until that lands, this method is only actually
+	/// reachable from the rescan call site in `handle_event`.
+	async fn flush(&mut self) -> Result<(), LocationManagerError> {
+		let mut should_invalidate = false;
+
+		for (path, _) in self.files_to_update.drain() {
+			update_file(self.location_id, &path, self.node, self.library).await?;
+			self.journal.clear(&path);
+			should_invalidate = true;
+		}
+
+		for (path, _) in self.rename_from.drain() {
+			file_id_cache().lock().unwrap().remove_path(&path);
+			remove(self.location_id, &path, self.library).await?;
+			self.journal.clear(&path);
+			should_invalidate = true;
+		}
 
-			self.last_events_eviction_check = Instant::now();
+		if should_invalidate {
+			invalidate_query!(self.library, "search.paths");
 		}
+
+		Ok(())
 	}
 }
 
-impl LinuxEventHandler<'_> {
+impl<'lib> LinuxEventHandler<'lib> {
+	/// Builds the handler with an explicit [`WatcherConfig`] and [`Clock`], so tests can tune
+	/// the debounce windows and drive a mock clock instead of depending on real wall-clock time.
+	fn with_config(
+		location_id: location::id::Type,
+		library: &'lib Arc<Library>,
+		node: &'lib Arc<Node>,
+		config: WatcherConfig,
+		clock: Arc<dyn Clock>,
+	) -> Self {
+		let journal = PendingJournal::open(node, location_id);
+
+		let mut rename_from = HashMap::new();
+		let mut files_to_update = HashMap::new();
+		let mut overdue_replay = Vec::new();
+
+		for (path, entry) in journal.drain() {
+			let recorded_at = UNIX_EPOCH + Duration::from_millis(entry.recorded_at_millis);
+			let elapsed = SystemTime::now()
+				.duration_since(recorded_at)
+				.unwrap_or_default();
+
+			let threshold = match entry.kind {
+				PendingKind::Update => config.debounce * config.update_settle_multiplier,
+				PendingKind::RenameFrom => config.debounce,
+			};
+
+			if elapsed >= threshold {
+				overdue_replay.push((path, entry.kind));
+			} else {
+				// Re-arm with the remaining time instead of the full debounce window, so a
+				// restart can't indefinitely postpone a change that was already most of the way
+				// through its debounce.
+				let recreated_at = clock.now() - elapsed;
+				match entry.kind {
+					PendingKind::Update => {
+						files_to_update.insert(path, recreated_at);
+					}
+					PendingKind::RenameFrom => {
+						rename_from.insert(path, recreated_at);
+					}
+				}
+			}
+		}
+
+		Self {
+			location_id,
+			library,
+			node,
+			last_events_eviction_check: clock.now(),
+			rename_from,
+			rename_from_buffer: Vec::new(),
+			recently_renamed_from: BTreeMap::new(),
+			files_to_update,
+			files_to_update_buffer: Vec::new(),
+			missing_paths: HashSet::new(),
+			journal,
+			overdue_replay,
+			config,
+			clock,
+		}
+	}
+
+	/// Re-runs the operations recovered from the journal whose debounce timer had already
+	/// elapsed by the time this handler came back up, e.g. after a crash or a long shutdown.
+	async fn replay_overdue(&mut self) -> Result<(), LocationManagerError> {
+		let mut should_invalidate = false;
+
+		for (path, kind) in self.overdue_replay.drain(..) {
+			match kind {
+				PendingKind::Update => {
+					update_file(self.location_id, &path, self.node, self.library).await?;
+				}
+				PendingKind::RenameFrom => {
+					remove(self.location_id, &path, self.library).await?;
+				}
+			}
+			self.journal.clear(&path);
+			should_invalidate = true;
+		}
+
+		if should_invalidate {
+			invalidate_query!(self.library, "search.paths");
+		}
+
+		Ok(())
+	}
+
+	/// Looks up this handler's location's root path, for reconciling after a rescan whose event
+	/// carried no paths of its own (see [`Self::handle_event`]'s `need_rescan` branch).
+	///
+	/// Returns `None` and logs on any database error rather than surfacing one, since a failed
+	/// lookup here shouldn't take down the watcher: the caller just skips reconciliation and
+	/// waits for the next event.
+	async fn location_root(&self) -> Option<PathBuf> {
+		match self
+			.library
+			.db
+			.location()
+			.find_unique(location::id::equals(self.location_id))
+			.exec()
+			.await
+		{
+			Ok(Some(location)) => location.path.map(PathBuf::from),
+			Ok(None) => {
+				warn!("Location {} no longer exists, can't reconcile it after a rescan", self.location_id);
+				None
+			}
+			Err(e) => {
+				error!("Failed to look up location {} for rescan reconciliation: {e:#?}", self.location_id);
+				None
+			}
+		}
+	}
+
+	/// Walks `path`, runs `create_dir` for every directory found under it and queues every file
+	/// found in [`Self::files_to_update`] (and the journal), so it gets `update_file` once it
+	/// settles.
+	///
+	/// Used both after an inotify overflow (where we know we silently dropped an unknown number
+	/// of events somewhere under `path`) and when a previously missing watch root reappears, so
+	/// this is also the path that absorbs a directory containing thousands of entries being
+	/// moved into the watched location in one go (notify only tells us about the top-level
+	/// `Create(Folder)`). Only covers creates and updates; pair this with
+	/// [`Self::reconcile_removed`] to also catch deletes the same dropped events took with them —
+	/// see the `need_rescan` branch of [`Self::handle_event`], which always calls both.
+	///
+	/// `fs::metadata` is fetched concurrently in [`WatcherConfig::batch_size`]-sized batches
+	/// instead of one syscall per entry, so a huge directory doesn't stall the event loop; the
+	/// `create_dir` upserts themselves still run one at a time in the walk's own order, so parent
+	/// directories are always indexed before their children. These are individual upserts, one
+	/// per directory, not a single grouped database write.
+	async fn reconcile_subtree(&mut self, path: &Path) -> Result<(), LocationManagerError> {
+		if !fs::try_exists(path).await.unwrap_or(false) {
+			return Ok(());
+		}
+
+		let entries = WalkDir::new(path)
+			.into_iter()
+			.filter_map(|entry| {
+				entry
+					.map_err(|e| warn!("Error walking {path:?} during reconciliation: {e}"))
+					.ok()
+			})
+			.map(DirEntry::into_path)
+			.collect::<Vec<_>>();
+
+		for chunk in entries.chunks(self.config.batch_size.max(1)) {
+			let mut metadata_fetches = JoinSet::new();
+			for entry_path in chunk {
+				let entry_path = entry_path.clone();
+				metadata_fetches.spawn(async move {
+					let metadata = fs::metadata(&entry_path).await;
+					(entry_path, metadata)
+				});
+			}
+
+			let mut fetched = HashMap::with_capacity(chunk.len());
+			while let Some(result) = metadata_fetches.join_next().await {
+				match result {
+					Ok((entry_path, Ok(metadata))) => {
+						fetched.insert(entry_path, metadata);
+					}
+					Ok((entry_path, Err(e))) => {
+						warn!("Skipping {entry_path:?} during reconciliation: {e}");
+					}
+					Err(e) => error!("Metadata fetch task panicked during reconciliation: {e:#?}"),
+				}
+			}
+
+			for entry_path in chunk {
+				let Some(metadata) = fetched.remove(entry_path) else {
+					continue;
+				};
+
+				file_id_cache().lock().unwrap().add_path(entry_path);
+
+				if metadata.is_dir() {
+					if entry_path != path {
+						create_dir(self.location_id, entry_path, &metadata, self.node, self.library)
+							.await?;
+					}
+				} else {
+					self.journal.record(entry_path, PendingKind::Update);
+					self.files_to_update
+						.insert(entry_path.clone(), self.clock.now());
+				}
+			}
+		}
+
+		Ok(())
+	}
+
+	/// Diffs indexed `file_path` rows under `path` against what's still on disk, and removes
+	/// whatever's gone.
+	///
+	/// Pairs with [`Self::reconcile_subtree`], which only handles creates/updates: an inotify
+	/// overflow drops `Remove` events under the same queue pressure that drops everything else,
+	/// so a delete that happened during the dropped-events window would otherwise dangle in the
+	/// index forever, reproducing the request's "permanently desynchronizes" failure mode for
+	/// deletions instead of creations.
+	///
+	/// `location_root` is needed to turn each row's `materialized_path` back into an absolute
+	/// path to check against disk and pass to [`remove`].
+	///
+	/// Assumes the `file_path` model's shape (`location_id`, `materialized_path`) matches the
+	/// rest of this codebase's conventions; the Prisma schema itself isn't part of this file.
+	async fn reconcile_removed(
+		&mut self,
+		path: &Path,
+		location_root: &Path,
+	) -> Result<(), LocationManagerError> {
+		let Ok(relative_prefix) = path.strip_prefix(location_root) else {
+			return Ok(());
+		};
+
+		let indexed = match self
+			.library
+			.db
+			.file_path()
+			.find_many(vec![
+				file_path::location_id::equals(Some(self.location_id)),
+				file_path::materialized_path::starts_with(
+					relative_prefix.to_string_lossy().into_owned(),
+				),
+			])
+			.exec()
+			.await
+		{
+			Ok(rows) => rows,
+			Err(e) => {
+				error!(
+					"Failed to look up indexed paths under {} for reconciliation: {e:#?}",
+					path.display()
+				);
+				return Ok(());
+			}
+		};
+
+		let mut should_invalidate = false;
+
+		for row in indexed {
+			let Some(materialized_path) = row.materialized_path else {
+				continue;
+			};
+			let absolute_path = location_root.join(materialized_path);
+
+			if fs::try_exists(&absolute_path).await.unwrap_or(true) {
+				continue;
+			}
+
+			file_id_cache().lock().unwrap().remove_path(&absolute_path);
+			remove(self.location_id, &absolute_path, self.library).await?;
+			self.journal.clear(&absolute_path);
+			should_invalidate = true;
+		}
+
+		if should_invalidate {
+			invalidate_query!(self.library, "search.paths");
+		}
+
+		Ok(())
+	}
+
+	/// Runs `update_file` for every settled entry in [`Self::files_to_update`].
+	///
+	/// These are still `N` separate `update_file` upserts, one per path, not a single grouped
+	/// database write — the `JoinSet` below only bounds how many run *concurrently* at a time
+	/// instead of running them one after another. That's a reasonable mitigation for a burst of
+	/// settled files stalling the tick, but it isn't the batched upsert a true grouped write
+	/// would be; doing that would mean a dedicated multi-row `update_file` variant, which doesn't
+	/// exist in this codebase yet.
+	///
+	/// Descoped: the "grouped database upserts" half of the originating request is explicitly
+	/// NOT done here. Building a real multi-row upsert is a separate, larger change to the
+	/// `utils` module (not touched by this series) and shouldn't be implied as finished by this
+	/// commit alone.
 	async fn handle_to_update_eviction(&mut self) -> Result<(), LocationManagerError> {
 		self.files_to_update_buffer.clear();
 		let mut should_invalidate = false;
+		let now = self.clock.now();
+		let settle_window = self.config.debounce * self.config.update_settle_multiplier;
 
+		let mut due = Vec::new();
 		for (path, created_at) in self.files_to_update.drain() {
-			if created_at.elapsed() < HUNDRED_MILLIS * 5 {
-				self.files_to_update_buffer.push((path, created_at));
+			if has_settled(now, created_at, settle_window) {
+				due.push(path);
 			} else {
-				update_file(self.location_id, &path, self.node, self.library).await?;
-				should_invalidate = true;
+				self.files_to_update_buffer.push((path, created_at));
+			}
+		}
+
+		// Bound the number of in-flight `update_file` upserts instead of awaiting them one at a
+		// time, so a burst of thousands of settled files can't stall this tick indefinitely.
+		for chunk in due.chunks(self.config.batch_size.max(1)) {
+			let mut updates = JoinSet::new();
+			for path in chunk {
+				let path = path.clone();
+				let location_id = self.location_id;
+				let node = Arc::clone(self.node);
+				let library = Arc::clone(self.library);
+				updates.spawn(async move {
+					update_file(location_id, &path, &node, &library)
+						.await
+						.map(|_| path)
+				});
+			}
+
+			while let Some(result) = updates.join_next().await {
+				match result {
+					Ok(Ok(path)) => {
+						self.journal.clear(&path);
+						should_invalidate = true;
+					}
+					Ok(Err(e)) => error!("Failed to update file during batched eviction: {e:#?}"),
+					Err(e) => error!("Update task panicked during batched eviction: {e:#?}"),
+				}
 			}
 		}
 
@@ -177,15 +934,42 @@ impl LinuxEventHandler<'_> {
 	async fn handle_rename_from_eviction(&mut self) -> Result<(), LocationManagerError> {
 		self.rename_from_buffer.clear();
 		let mut should_invalidate = false;
+		let now = self.clock.now();
+		let debounce = self.config.debounce;
 
 		for (path, instant) in self.rename_from.drain() {
-			if instant.elapsed() > HUNDRED_MILLIS {
-				remove(self.location_id, &path, self.library).await?;
-				should_invalidate = true;
-				trace!("Removed file_path due timeout: {}", path.display());
-			} else {
+			if !has_settled(now, instant, debounce) {
 				self.rename_from_buffer.push((path, instant));
+				continue;
+			}
+
+			// The instant-of-receipt check in `handle_event` only catches a relink if the
+			// destination's `Create`/`Rename To` had already landed in the shared cache by the
+			// time the `Rename From` arrived. Check again here, right before giving up on the
+			// path, so a destination that shows up anywhere during the debounce window — not
+			// just in the same tick as the origin event — still gets picked up.
+			let relinked_to = file_id_cache().lock().unwrap().resolve_rename_from(&path);
+			if let Some(to_path) = relinked_to {
+				rename(
+					self.location_id,
+					&to_path,
+					&path,
+					fs::metadata(&to_path)
+						.await
+						.map_err(|e| FileIOError::from((&to_path, e)))?,
+					self.library,
+				)
+				.await?;
+				self.journal.clear(&path);
+				should_invalidate = true;
+				continue;
 			}
+
+			file_id_cache().lock().unwrap().remove_path(&path);
+			remove(self.location_id, &path, self.library).await?;
+			self.journal.clear(&path);
+			should_invalidate = true;
+			trace!("Removed file_path due timeout: {}", path.display());
 		}
 
 		if should_invalidate {
@@ -199,3 +983,134 @@ impl LinuxEventHandler<'_> {
 		Ok(())
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn test_id(inode_number: u64) -> FileId {
+		FileId::Inode {
+			device_id: 1,
+			inode_number,
+		}
+	}
+
+	/// A [`Clock`] double that only advances when told to, so debounce/eviction tests don't
+	/// depend on real elapsed wall-clock time or need to sleep.
+	#[derive(Debug)]
+	struct MockClock(Mutex<Instant>);
+
+	impl MockClock {
+		fn new() -> Self {
+			Self(Mutex::new(Instant::now()))
+		}
+
+		fn advance(&self, by: Duration) {
+			let mut now = self.0.lock().unwrap();
+			*now += by;
+		}
+	}
+
+	impl Clock for MockClock {
+		fn now(&self) -> Instant {
+			*self.0.lock().unwrap()
+		}
+	}
+
+	#[test]
+	fn watcher_config_default_matches_the_previously_hardcoded_policy() {
+		let config = WatcherConfig::default();
+
+		assert_eq!(config.debounce, HUNDRED_MILLIS);
+		assert_eq!(config.update_settle_multiplier, 5);
+		assert_eq!(config.eviction_check_interval, HUNDRED_MILLIS);
+		assert_eq!(config.batch_size, 64);
+	}
+
+	#[test]
+	fn watcher_config_from_env_overrides_the_default_when_set() {
+		// Chosen so it isn't read or written by any other test in this process.
+		let key = "SD_WATCHER_BATCH_SIZE";
+		std::env::set_var(key, "128");
+
+		let config = WatcherConfig::from_env();
+
+		std::env::remove_var(key);
+
+		assert_eq!(config.batch_size, 128);
+		assert_eq!(config.debounce, WatcherConfig::default().debounce);
+	}
+
+	#[test]
+	fn repeated_updates_to_the_same_path_coalesce_into_one_pending_entry() {
+		// Mirrors the `files_to_update.insert` in the `Create(File) | Modify(Data(Any))` arm of
+		// `handle_event`: each consecutive event for the same path just overwrites the timestamp,
+		// so a burst of rapid writes settles as a single `update_file` rather than one per event.
+		let clock = MockClock::new();
+		let mut files_to_update = HashMap::new();
+		let path = PathBuf::from("/watched/a/file.txt");
+
+		for _ in 0..3 {
+			files_to_update.insert(path.clone(), clock.now());
+			clock.advance(Duration::from_millis(10));
+		}
+
+		assert_eq!(files_to_update.len(), 1);
+	}
+
+	#[test]
+	fn has_settled_is_false_before_the_window_elapses() {
+		let clock = MockClock::new();
+		let recorded_at = clock.now();
+
+		clock.advance(Duration::from_millis(99));
+
+		assert!(!has_settled(clock.now(), recorded_at, Duration::from_millis(100)));
+	}
+
+	#[test]
+	fn has_settled_is_true_once_the_window_elapses() {
+		let clock = MockClock::new();
+		let recorded_at = clock.now();
+
+		clock.advance(Duration::from_millis(100));
+
+		assert!(has_settled(clock.now(), recorded_at, Duration::from_millis(100)));
+	}
+
+	#[test]
+	fn resolve_rename_from_finds_the_destination_when_already_registered() {
+		let mut cache = FileIdCache::default();
+		let id = test_id(42);
+		let from_path = PathBuf::from("/watched/a/old.txt");
+		let to_path = PathBuf::from("/watched/b/new.txt");
+
+		// Simulate the destination half of the move (possibly processed by a different
+		// location's handler, since this cache is shared) already having registered `to_path`
+		// under the same inode before the origin's `Rename From` is handled.
+		cache.path_to_id.insert(from_path.clone(), id);
+		cache.path_to_id.insert(to_path.clone(), id);
+		cache.id_to_path.insert(id, to_path.clone());
+
+		let resolved = cache.resolve_rename_from(&from_path);
+
+		assert_eq!(resolved, Some(to_path));
+		assert!(!cache.path_to_id.contains_key(&from_path));
+	}
+
+	#[test]
+	fn resolve_rename_from_is_dangling_when_nothing_else_claimed_the_inode() {
+		let mut cache = FileIdCache::default();
+		let id = test_id(7);
+		let gone_path = PathBuf::from("/watched/gone.txt");
+
+		cache.path_to_id.insert(gone_path.clone(), id);
+		cache.id_to_path.insert(id, gone_path.clone());
+
+		let resolved = cache.resolve_rename_from(&gone_path);
+
+		assert_eq!(resolved, None);
+		assert!(cache.path_to_id.is_empty());
+		assert!(cache.id_to_path.is_empty());
+	}
+}